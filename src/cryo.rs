@@ -1,5 +1,6 @@
 //! This module contains macros and structs for GPGPU
 
+use std::{error, fmt};
 use std::sync::Arc;
 use vulkano::{
     instance,
@@ -7,6 +8,8 @@ use vulkano::{
     descriptor::*,
     pipeline::*,
     buffer::*,
+    command_buffer::{AutoCommandBuffer, AutoCommandBufferBuilder, CommandBuffer, CommandBufferExecError, DispatchError},
+    sync::FlushError,
 };
 
 /// Creates an [`Arc`](std::sync::Arc)<[`PersistantDescriptorSet`](vulkano::pipeline::ComputePipeline)> from list of [`buffer`](vulkano::buffer) and a [`pipeline`](vulkano::pipeline)
@@ -166,6 +169,44 @@ macro_rules! shader {
 /// }
 /// ");
 /// ```
+/// 
+/// When using a shader created with [`shader!`], specialization constants can be given with `spec:`.
+/// ```
+/// # #[macro_use]
+/// # extern crate geyser;
+/// use geyser::Cryo;
+/// 
+/// geyser::shader! {
+///     name: multiply,
+///     src: "
+/// #version 450
+/// 
+/// layout(constant_id = 0) const uint multiple = 1;
+/// 
+/// layout(set = 0, binding = 0) buffer Data {
+///     uint data[];
+/// } buf;
+/// 
+/// void main() {
+///     uint idx = gl_GlobalInvocationID.x;
+/// 
+///     buf.data[idx] = idx * multiple;
+/// }
+///     "
+/// }
+/// 
+/// fn main() {
+///     let cryo = Cryo::new();
+/// 
+///     let pipeline = compute_pipeline!(
+///         cryo,
+///         multiply,
+///         spec: multiply::SpecializationConstants {
+///             multiple: 12,
+///         }
+///     );
+/// }
+/// ```
 #[macro_export]
 macro_rules! compute_pipeline {
     ($instance:expr, $tt:tt: $source_code:expr) => {
@@ -190,6 +231,9 @@ macro_rules! compute_pipeline {
         }
     };
     ($instance:expr, $shader:ident) => {
+        $crate::compute_pipeline!($instance, $shader, spec: ())
+    };
+    ($instance:expr, $shader:ident, spec: $spec:expr) => {
         {
             use geyser::Pipeline;
             use geyser::vulkano_shaders;
@@ -198,7 +242,7 @@ macro_rules! compute_pipeline {
 
             let pipeline = Arc::new(ComputePipeline::new($instance.device(), 
                      &$shader::Shader::load($instance.device()).unwrap().main_entry_point(), 
-                     &()).unwrap());
+                     &$spec).unwrap());
 
             Pipeline::new(pipeline, $instance.device(), $instance.queue())
         }
@@ -242,22 +286,85 @@ pub struct Cryo {
 impl Cryo {
     /// Initializes vulkan and creates a new [`Cryo`]. This funtion should only be called **once**.
     /// 
-    /// It uses the first [`QueueFamily`](instance::QueueFamily) that supports graphics and the first [`Queue`](device::Queue) in that [`QueueFamily`](instance::QueueFamily)
+    /// It uses the first [`QueueFamily`](instance::QueueFamily) that supports graphics and compute and the first [`Queue`](device::Queue) in that [`QueueFamily`](instance::QueueFamily)
     pub fn new() -> Cryo {
+        Cryo::with_features(&device::Features::none())
+    }
+
+    /// Like [`Cryo::new`], but enables the requested [`Features`](device::Features) on the device.
+    ///
+    /// Use [`PhysicalDevice::supported_features`](instance::PhysicalDevice::supported_features) to see what the device supports.
+    /// 
+    /// # Panics
+    /// Panics if any of the requested features isn't supported, 
+    /// or if no [`QueueFamily`](instance::QueueFamily) supports both graphics and compute.
+    pub fn with_features(features: &device::Features) -> Cryo {
+        Cryo::with_queue_family(features, |q| q.supports_graphics())
+            .expect("No queue families support graphics and compute")
+    }
+
+    /// Like [`Cryo::with_features`], but uses the first [`QueueFamily`](instance::QueueFamily) that supports compute and for which `filter` returns `true`.
+    /// 
+    /// If no [`QueueFamily`](instance::QueueFamily) matches `filter`, the first one that supports both graphics and compute is used instead,
+    /// so only one instance is ever created. Use [`Cryo::queue`] to see which [`QueueFamily`](instance::QueueFamily) was picked.
+    /// Returns [`None`] if neither kind of [`QueueFamily`](instance::QueueFamily) exists.
+    ///
+    /// This can be used to pick a queue family without graphics support, which lets your calculations run alongside graphics work on devices that have one.
+    /// 
+    /// # Panics
+    /// Panics if vulkan can't be initialized, if no physical device is found or if the device can't be created,
+    /// for example because one of the requested features isn't supported.
+    /// 
+    /// # Example
+    /// ```no_run
+    /// # extern crate geyser;
+    /// use geyser::Cryo;
+    /// use geyser::vulkano::device::Features;
+    /// 
+    /// // Use a dedicated compute queue family if there is one
+    /// let cryo = Cryo::with_queue_family(&Features::none(), |q| !q.supports_graphics())
+    ///     .expect("No queue families support compute");
+    /// 
+    /// if cryo.queue().family().supports_graphics() {
+    ///     println!("No dedicated compute queue family, using one that also supports graphics");
+    /// }
+    /// ```
+    pub fn with_queue_family<F>(features: &device::Features, mut filter: F) -> Option<Cryo>
+        where F: FnMut(&instance::QueueFamily) -> bool
+    {
         let instance = instance::Instance::new(None, &instance::InstanceExtensions::none(), None).expect("Failed to create instance");
 
         let physical = instance::PhysicalDevice::enumerate(&instance).next().expect("Fail to create physical instance");
 
         let queue_family = physical.queue_families()
-            .find(|q| q.supports_graphics())
-            .expect("No queue families support graphics");
+            .find(|q| q.supports_compute() && filter(q))
+            .or_else(|| physical.queue_families().find(|q| q.supports_compute() && q.supports_graphics()))?;
 
-        let (device, mut queues) = device::Device::new(physical, &device::Features::none(), &device::DeviceExtensions::none(),
+        let (device, mut queues) = device::Device::new(physical, features, &device::DeviceExtensions::none(),
                                                [(queue_family, 0.5)].iter().cloned()).expect("failed to create device");
 
         let queue = queues.next().unwrap();
 
 
+        Some(Cryo {
+            instance,
+            device,
+            queue,
+        })
+    }
+
+    /// Creates a [`Cryo`] from an [`Instance`](instance::Instance), [`Device`](device::Device) and [`Queue`](device::Queue) you already have.
+    ///
+    /// This is useful when using geyser next to other code that has already initialized vulkan, since no new instance is created.
+    ///
+    /// # Panics
+    /// Panics if `device` wasn't created from `instance`, if `queue` doesn't belong to `device` 
+    /// or if the [`QueueFamily`](instance::QueueFamily) of `queue` doesn't support compute.
+    pub fn from_parts(instance: Arc<instance::Instance>, device: Arc<device::Device>, queue: Arc<device::Queue>) -> Cryo {
+        assert!(Arc::ptr_eq(device.instance(), &instance), "The device wasn't created from the instance");
+        assert!(Arc::ptr_eq(queue.device(), &device), "The queue doesn't belong to the device");
+        assert!(queue.family().supports_compute(), "The queue family doesn't support compute");
+
         Cryo {
             instance,
             device,
@@ -280,11 +387,79 @@ impl Cryo {
         self.queue.clone()
     }
 
+    /// Returns the [`PhysicalDevice`](instance::PhysicalDevice) the cryo was created from.
+    ///
+    /// This can be used to look at the [`MemoryHeap`](instance::MemoryHeap)s and [`MemoryType`](instance::MemoryType)s of the device,
+    /// for example to decide how large your buffers can be.
+    ///
+    /// # Example
+    /// ```
+    /// # extern crate geyser;
+    /// use geyser::Cryo;
+    ///
+    /// let cryo = Cryo::new();
+    ///
+    /// for heap in cryo.physical_device().memory_heaps() {
+    ///     println!("Heap: {} bytes, device local: {}", heap.size(), heap.is_device_local());
+    /// }
+    /// ```
+    pub fn physical_device(&self) -> instance::PhysicalDevice {
+        self.device.physical_device()
+    }
+
+    /// Blocks until all work submitted to the [`Queue`](device::Queue) in the cryo has finished.
+    /// 
+    /// # Panics
+    /// Panics if [`Cryo::try_wait_idle`] returns an error.
+    pub fn wait_idle(&self) {
+        self.try_wait_idle().unwrap();
+    }
+
+    /// Blocks until all work submitted to the [`Queue`](device::Queue) in the cryo has finished and returns a [`Result`]
+    pub fn try_wait_idle(&self) -> Result<(), vulkano::OomError> {
+        self.queue.wait()
+    }
+
 
     /// Creates a [`CpuAccessibleBuffer`] containing the data from the supplied [`Vec`] and returns a [`Result`]
     pub fn buffer_from_data<D: 'static>(&self, data: Vec<D>) -> Result<Arc<CpuAccessibleBuffer<[D]>>, vulkano::memory::DeviceMemoryAllocError> {
         CpuAccessibleBuffer::from_iter(self.device(), vulkano::buffer::BufferUsage::all(), data.into_iter())
     }
+
+    /// Creates an [`AutoCommandBufferBuilder`], lets `record` add commands to it, executes the result and waits for it to finish.
+    /// 
+    /// If `record` returns an error nothing is executed and it is returned as [`SubmitError::Record`].
+    /// Errors from executing or waiting for the commands are returned as [`SubmitError::Compute`].
+    /// 
+    /// This **blocks** until the commands are finished.
+    /// 
+    /// # Panics
+    /// Panics if the command buffer can't be allocated or built.
+    /// 
+    /// # Example
+    /// ```
+    /// # extern crate geyser;
+    /// use geyser::Cryo;
+    /// 
+    /// let cryo = Cryo::new();
+    /// 
+    /// let src = cryo.buffer_from_data(vec![42u32; 69]).expect("Failed to create buffer");
+    /// let dst = cryo.buffer_from_data(vec![0u32; 69]).expect("Failed to create buffer");
+    /// 
+    /// cryo.submit_immediate(|builder| builder.copy_buffer(src.clone(), dst.clone()))
+    ///     .expect("Failed to copy buffer");
+    /// ```
+    pub fn submit_immediate<F, E>(&self, record: F) -> Result<(), SubmitError<E>>
+        where F: FnOnce(AutoCommandBufferBuilder) -> Result<AutoCommandBufferBuilder, E>
+    {
+        let builder = AutoCommandBufferBuilder::new(self.device.clone(), self.queue.family()).unwrap();
+
+        let command_buffer = record(builder).map_err(SubmitError::Record)?.build().unwrap();
+
+        execute_and_wait(command_buffer, self.queue.clone())?;
+
+        Ok(())
+    }
 }
 
 
@@ -326,6 +501,9 @@ impl<C: 'static> Pipeline<C> {
     /// [`AutoCommandBuffer::execute`](vulkano::command_buffer::CommandBuffer::execute) on it and waits for it to finish. 
     /// 
     /// This **blocks** until the calculation is finished.
+    /// 
+    /// # Panics
+    /// Panics if [`Pipeline::try_dispatch`] returns an error, for example when `size` exceeds the max compute work group count of the device.
     pub fn dispatch<L: 'static, R: 'static, Pc>(&self, size: [u32; 3], 
         set: Arc<descriptor_set::PersistentDescriptorSet<L, R>>, push_constants: Pc)
         
@@ -335,16 +513,278 @@ impl<C: 'static> Pipeline<C> {
               ComputePipeline<C>: ComputePipelineAbstract,
               descriptor_set::PersistentDescriptorSet<L, R>: DescriptorSet,
     {
-        use vulkano::command_buffer::CommandBuffer;
-        use vulkano::sync::GpuFuture;
+        self.try_dispatch(size, set, push_constants).unwrap();
+    }
+
+    /// Like [`Pipeline::dispatch`], but returns a [`Result`] instead of panicking.
+    /// 
+    /// If `size` exceeds the max compute work group count of the device a [`ComputeError::TooLarge`] is returned,
+    /// in which case the work should be split into multiple dispatches.
+    /// 
+    /// This **blocks** until the calculation is finished.
+    /// 
+    /// # Panics
+    /// Panics if the command buffer can't be allocated or built.
+    /// 
+    /// # Example
+    /// ```
+    /// # #[macro_use]
+    /// # extern crate geyser;
+    /// use geyser::{Cryo, ComputeError};
+    /// 
+    /// fn main() {
+    ///     let cryo = Cryo::new();
+    /// 
+    ///     let pipeline = compute_pipeline!(
+    ///         cryo,
+    ///         src: "
+    /// #version 450
+    /// 
+    /// layout(set = 0, binding = 0) buffer Data {
+    ///     uint data[];
+    /// } buf;
+    /// 
+    /// void main() {
+    ///     uint idx = gl_GlobalInvocationID.x;
+    /// 
+    ///     buf.data[idx] = idx;
+    /// }
+    /// ");
+    /// 
+    ///     let buf = cryo.buffer_from_data(vec![0u32; 69]).expect("Failed to create buffer");
+    /// 
+    ///     let set = descriptor_set!([buf], pipeline);
+    /// 
+    ///     // No device supports this many work groups, so an error is returned instead of a panic
+    ///     match pipeline.try_dispatch([u32::max_value(), 1, 1], set.clone(), ()) {
+    ///         Err(ComputeError::TooLarge { .. }) => (),
+    ///         other => panic!("Expected ComputeError::TooLarge, got {:?}", other),
+    ///     }
+    /// }
+    /// ```
+    pub fn try_dispatch<L: 'static, R: 'static, Pc>(&self, size: [u32; 3], 
+        set: Arc<descriptor_set::PersistentDescriptorSet<L, R>>, push_constants: Pc) -> Result<(), ComputeError>
+        
+        where L: Send + Sync,
+              R: Send + Sync,
+              C: Send + Sync,
+              ComputePipeline<C>: ComputePipelineAbstract,
+              descriptor_set::PersistentDescriptorSet<L, R>: DescriptorSet,
+    {
+        let max_size = self.device.physical_device().limits().max_compute_work_group_count();
+
+        if size.iter().zip(max_size.iter()).any(|(size, max)| size > max) {
+            return Err(ComputeError::TooLarge { size, max_size });
+        }
 
+        let command_buffer = self.try_record(size, set, push_constants)?;
+
+        self.try_execute(command_buffer)
+    }
+
+    /// Records the dispatch into an [`AutoCommandBuffer`](vulkano::command_buffer::AutoCommandBuffer) without executing it.
+    /// 
+    /// The command buffer can be executed as many times as you want with [`Pipeline::execute`], 
+    /// so a dispatch that doesn't change doesn't have to be recorded again every time.
+    /// The command buffer must **not** be submitted again while a previous submission of it is still running.
+    /// [`Pipeline::execute`] waits for the work to finish, so calling it repeatedly is fine.
+    /// 
+    /// # Panics
+    /// Panics if [`Pipeline::try_record`] returns an error, for example when `size` exceeds the max compute work group count of the device.
+    /// 
+    /// # Example
+    /// ```
+    /// # #[macro_use]
+    /// # extern crate geyser;
+    /// use geyser::Cryo;
+    /// 
+    /// fn main() {
+    ///     let cryo = Cryo::new();
+    /// 
+    ///     let pipeline = compute_pipeline!(
+    ///         cryo,
+    ///         src: "
+    /// #version 450
+    /// 
+    /// layout(set = 0, binding = 0) buffer Data {
+    ///     uint data[];
+    /// } buf;
+    /// 
+    /// void main() {
+    ///     uint idx = gl_GlobalInvocationID.x;
+    /// 
+    ///     buf.data[idx] += 1;
+    /// }
+    /// ");
+    /// 
+    ///     let buf = cryo.buffer_from_data(vec![0u32; 69]).expect("Failed to create buffer");
+    /// 
+    ///     let set = descriptor_set!([buf], pipeline);
+    /// 
+    ///     // Record the dispatch once
+    ///     let command_buffer = pipeline.record([69, 1, 1], set.clone(), ());
+    /// 
+    ///     // and execute it twice
+    ///     pipeline.execute(command_buffer.clone());
+    ///     pipeline.execute(command_buffer.clone());
+    /// 
+    ///     assert!(buf.read().expect("Failed to read from buffer").iter().all(|x| *x == 2));
+    /// }
+    /// ```
+    pub fn record<L: 'static, R: 'static, Pc>(&self, size: [u32; 3], 
+        set: Arc<descriptor_set::PersistentDescriptorSet<L, R>>, push_constants: Pc) -> Arc<AutoCommandBuffer>
+        
+        where L: Send + Sync,
+              R: Send + Sync,
+              C: Send + Sync,
+              ComputePipeline<C>: ComputePipelineAbstract,
+              descriptor_set::PersistentDescriptorSet<L, R>: DescriptorSet,
+    {
+        self.try_record(size, set, push_constants).unwrap()
+    }
+
+    /// Like [`Pipeline::record`], but returns the [`DispatchError`] instead of panicking.
+    /// 
+    /// This is returned if, for example, `size` exceeds the max compute work group count of the device.
+    /// 
+    /// # Panics
+    /// Panics if the command buffer can't be allocated or built.
+    pub fn try_record<L: 'static, R: 'static, Pc>(&self, size: [u32; 3], 
+        set: Arc<descriptor_set::PersistentDescriptorSet<L, R>>, push_constants: Pc) -> Result<Arc<AutoCommandBuffer>, DispatchError>
+        
+        where L: Send + Sync,
+              R: Send + Sync,
+              C: Send + Sync,
+              ComputePipeline<C>: ComputePipelineAbstract,
+              descriptor_set::PersistentDescriptorSet<L, R>: DescriptorSet,
+    {
         let command_buffer = vulkano::command_buffer::AutoCommandBufferBuilder::new(
             self.device.clone(), self.queue.clone().family()).unwrap()
-                .dispatch(size, self.pipeline.clone(), set.clone(), push_constants).unwrap()
+                .dispatch(size, self.pipeline.clone(), set.clone(), push_constants)?
                 .build().unwrap();
 
-        let finished = command_buffer.execute(self.queue.clone()).unwrap();
+        Ok(Arc::new(command_buffer))
+    }
+
+    /// Executes a command buffer created with [`Pipeline::record`] and waits for it to finish.
+    /// 
+    /// This **blocks** until the calculation is finished.
+    /// Since this waits, the same command buffer can be passed to it again afterwards.
+    /// 
+    /// # Panics
+    /// Panics if [`Pipeline::try_execute`] returns an error.
+    pub fn execute(&self, command_buffer: Arc<AutoCommandBuffer>) {
+        self.try_execute(command_buffer).unwrap();
+    }
+
+    /// Executes a command buffer created with [`Pipeline::record`], waits for it to finish and returns a [`Result`].
+    /// 
+    /// This **blocks** until the calculation is finished.
+    pub fn try_execute(&self, command_buffer: Arc<AutoCommandBuffer>) -> Result<(), ComputeError> {
+        execute_and_wait(command_buffer, self.queue.clone())
+    }
+}
+
+/// Executes `command_buffer` on `queue` and waits for it to finish.
+fn execute_and_wait<Cb>(command_buffer: Cb, queue: Arc<device::Queue>) -> Result<(), ComputeError>
+    where Cb: CommandBuffer + 'static
+{
+    use vulkano::sync::GpuFuture;
+
+    let finished = command_buffer.execute(queue)?;
+
+    finished.then_signal_fence_and_flush()?.wait(None)?;
+
+    Ok(())
+}
+
+/// Error that can happen when running work on the GPU through a [`Pipeline`].
+#[derive(Debug)]
+pub enum ComputeError {
+    /// The dispatch size exceeds the max compute work group count of the device.
+    TooLarge {
+        /// The requested dispatch size.
+        size: [u32; 3],
+        /// The max compute work group count of the device.
+        max_size: [u32; 3],
+    },
+    /// The dispatch couldn't be recorded, for example because it is too large for the device.
+    Dispatch(DispatchError),
+    /// The command buffer couldn't be submitted to the queue.
+    Execute(CommandBufferExecError),
+    /// Flushing or waiting for the work failed, for example because the device was lost.
+    Flush(FlushError),
+}
+
+impl From<DispatchError> for ComputeError {
+    fn from(err: DispatchError) -> Self {
+        ComputeError::Dispatch(err)
+    }
+}
+
+impl From<CommandBufferExecError> for ComputeError {
+    fn from(err: CommandBufferExecError) -> Self {
+        ComputeError::Execute(err)
+    }
+}
 
-        finished.then_signal_fence_and_flush().unwrap().wait(None).unwrap();
+impl From<FlushError> for ComputeError {
+    fn from(err: FlushError) -> Self {
+        ComputeError::Flush(err)
+    }
+}
+
+impl fmt::Display for ComputeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ComputeError::TooLarge { size, max_size } => write!(f, "dispatch size {:?} exceeds the max compute work group count {:?} of the device, split it into multiple dispatches", size, max_size),
+            ComputeError::Dispatch(ref err) => write!(f, "failed to record dispatch: {}", err),
+            ComputeError::Execute(ref err) => write!(f, "failed to execute command buffer: {}", err),
+            ComputeError::Flush(ref err) => write!(f, "failed to flush or wait for the command buffer: {}", err),
+        }
+    }
+}
+
+impl error::Error for ComputeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ComputeError::TooLarge { .. } => None,
+            ComputeError::Dispatch(ref err) => Some(err),
+            ComputeError::Execute(ref err) => Some(err),
+            ComputeError::Flush(ref err) => Some(err),
+        }
+    }
+}
+
+/// Error returned by [`Cryo::submit_immediate`].
+#[derive(Debug)]
+pub enum SubmitError<E> {
+    /// The `record` closure returned an error, so nothing was executed.
+    Record(E),
+    /// The recorded commands couldn't be executed or waited for.
+    Compute(ComputeError),
+}
+
+impl<E> From<ComputeError> for SubmitError<E> {
+    fn from(err: ComputeError) -> Self {
+        SubmitError::Compute(err)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for SubmitError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SubmitError::Record(ref err) => write!(f, "failed to record commands: {}", err),
+            SubmitError::Compute(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl<E: error::Error + 'static> error::Error for SubmitError<E> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            SubmitError::Record(ref err) => Some(err),
+            SubmitError::Compute(ref err) => Some(err),
+        }
     }
 }